@@ -4,16 +4,20 @@ use sqlparser::parser::{Parser, ParserError};
 use std::error;
 use std::fmt;
 use std::cell::RefCell;
-use traversal::DftPre;
-use std::thread::LocalKey;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use futures::stream::Stream;
 
 // Errors
-// We can wrap ParserError with a custom QueryParseError. 
+// We can wrap ParserError with a custom QueryParseError.
 
 #[derive(Debug)]
 enum QueryParseError {
     InvalidNode,
     NotImplemented,
+    RecursionLimitExceeded,
     Parse(ParserError)
 }
 
@@ -23,7 +27,9 @@ impl fmt::Display for QueryParseError {
             QueryParseError::NotImplemented =>
                 write!(f, "not implemented yet"),
             QueryParseError::InvalidNode =>
-            write!(f, "invalid node"), 
+            write!(f, "invalid node"),
+            QueryParseError::RecursionLimitExceeded =>
+                write!(f, "recursion limit exceeded while walking the AST"),
             QueryParseError::Parse(e) =>
                 write!(f, "parse: {:?}", e),
         }
@@ -39,12 +45,17 @@ impl From<ParserError> for QueryParseError {
 impl error::Error for QueryParseError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            QueryParseError::NotImplemented | QueryParseError::InvalidNode => None,
+            QueryParseError::NotImplemented | QueryParseError::InvalidNode
+                | QueryParseError::RecursionLimitExceeded => None,
             QueryParseError::Parse(ref e) => Some(e),
         }
     }
 }
 
+/// Default ceiling on how deep a walk may descend, mirroring sqlparser's
+/// own recursion counter default.
+const DEFAULT_RECURSION_LIMIT: usize = 50;
+
 // Tree
 
 /// AST nodes
@@ -52,170 +63,706 @@ impl error::Error for QueryParseError {
 enum Node {
     BinaryOperator(BinaryOperator),
     Expr(Expr),
+    Join(Join),
     Query(Query),
     SetExpr(SetExpr),
     Select(Select),
     SelectItem(SelectItem),
     Statement(Statement),
+    TableFactor(TableFactor),
     TableWithJoins(TableWithJoins),
+    Value(Value),
 }
 
-// Storage for a reference to the next node in stm_iter.
-// We can't return the node position (usize) itself as `traversal`
-// insists to return a reference. We also can't return a reference
-// to an element on the stack as it wouldn't live long enough.
-// So we allocate the NEXT storage on the heap, put an element there
-// and return a reference to this storage.
-thread_local!(static NEXT: RefCell<usize> = RefCell::new(0));
-// Storage for our custom AST nodes. We can't make it a part of the
-// stm_iterator because of the borrow checker. It believes that in
-// this case iterator "returns a reference to a captured variable which
-// escapes the closure body". Though I suggest it is a false positive
-// error, we can't make the code compile. So, global storage for the
-// nodes outside of the iterator structure is a solution.
-thread_local!(static NODES: RefCell<Nodes> = RefCell::new(Nodes::new()));
-
-fn next_put(id: usize) {
-    NEXT.with(|rc_id| { *rc_id.borrow_mut() = id; })
+/// One segment of a JSON-pointer-style path into the AST: `Field` steps
+/// into a named child, `Index` steps into a positional one.
+#[derive(Debug, Clone)]
+enum PathSegment {
+    Field(&'static str),
+    Index(usize),
 }
 
-fn next_get() -> usize {
-    NEXT.with(|rc_id| { *rc_id.borrow() })
+/// The node `nearest_mut` reached, plus how many leading `path` segments
+/// actually matched (less than the full path means it ran out of
+/// structure and stopped at the closest ancestor).
+struct NodeCursor<'a> {
+    id: usize,
+    node: &'a mut Node,
+    matched: usize,
 }
 
-fn nodes_next_id() -> usize {
-    NODES.with(|rc_nodes| {
-        rc_nodes.borrow().next_id()
-    })
+/// Walks parent links toward the root one id at a time.
+struct Ancestors<'a> {
+    arena: &'a Arena,
+    current: Option<usize>,
 }
 
-#[derive(Debug)]
-struct Nodes {
-    arena: Vec<Node>,
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let current = self.current?;
+        self.current = self.arena.parent_of(current);
+        Some(current)
+    }
 }
 
-/// Iterator over statement node's children
-struct StatementIterator {
-    /// current node id in the NODES list
-    current: usize,
-    /// keep the state
-    step: RefCell<usize>,
+/// Owned arena backing one parse's AST. Replaces the old `thread_local!`
+/// `NEXT`/`NODES` globals, so two parses never share state.
+#[derive(Debug)]
+struct Arena {
+    nodes: Vec<Node>,
+    /// `children[id]` holds the ids of `id`'s children, in discovery order.
+    children: Vec<Vec<usize>>,
+    /// `parents[id]` is the id of `id`'s parent, or `None` for the root.
+    parents: Vec<Option<usize>>,
+    /// First error raised while descending, if any; `PreOrder` sets this
+    /// when it hits its recursion limit, since `StatementIterator::next`
+    /// can't return a `Result` itself.
+    error: Option<QueryParseError>,
 }
 
-impl Nodes {
+impl Arena {
     fn new() -> Self {
-        Nodes {
-            arena: Vec::new()
+        Arena {
+            nodes: Vec::new(),
+            children: Vec::new(),
+            parents: Vec::new(),
+            error: None,
         }
     }
 
-    fn next_id(&self) -> usize {
-        self.arena.len()
+    fn get(&self, id: usize) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    fn push(&mut self, node: Node) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(node);
+        self.children.push(Vec::new());
+        self.parents.push(None);
+        id
     }
 
-    
-    fn new_node(&mut self, node: Node) -> usize {
-        let id = self.next_id();
-        self.arena.push(node);
+    /// Like `push`, but also records the new node as a child of `parent`.
+    fn push_child(&mut self, parent: usize, node: Node) -> usize {
+        let id = self.push(node);
+        self.children[parent].push(id);
+        self.parents[id] = Some(parent);
         id
     }
+
+    /// The id of `id`'s parent, or `None` if `id` is the root.
+    fn parent_of(&self, id: usize) -> Option<usize> {
+        self.parents.get(id).copied().flatten()
+    }
+
+    /// Walks parent links from `id`'s parent up to and including the root;
+    /// `id` itself is not yielded.
+    fn ancestors(&self, id: usize) -> Ancestors<'_> {
+        Ancestors { arena: self, current: self.parent_of(id) }
+    }
+
+    /// The id of the root reached by following `id`'s parent links,
+    /// or `id` itself if it has no parent.
+    fn root_of(&self, id: usize) -> usize {
+        self.ancestors(id).last().unwrap_or(id)
+    }
+
+    /// Resolves a single path segment against `id`'s children, using the
+    /// same layout `StatementIterator::next` expands each node kind into.
+    fn resolve_segment(&self, id: usize, segment: &PathSegment) -> Option<usize> {
+        let children = self.children.get(id)?;
+        match (self.nodes.get(id)?, segment) {
+            (Node::Statement(Statement::Query(_)), PathSegment::Field("body")) =>
+                children.first().copied(),
+            (Node::Query(_), PathSegment::Field("body")) =>
+                children.first().copied(),
+            // "from" addresses the whole FROM list, not a single table, so
+            // it stays on the Select node; a following `Index(i)` is what
+            // actually picks a table out of that list (see below).
+            (Node::SetExpr(SetExpr::Select(_)), PathSegment::Field("from")) => Some(id),
+            (Node::SetExpr(SetExpr::Select(select)), PathSegment::Field("selection")) => {
+                children.get(select.projection.len() + select.from.len()).copied()
+            },
+            (Node::SetExpr(SetExpr::Select(select)), PathSegment::Index(i)) => {
+                children.get(select.projection.len() + i).copied()
+            },
+            (Node::TableWithJoins(_), PathSegment::Field("relation")) => children.first().copied(),
+            (Node::TableWithJoins(_), PathSegment::Index(i)) => children.get(1 + i).copied(),
+            (Node::Join(_), PathSegment::Field("relation")) => children.first().copied(),
+            _ => None,
+        }
+    }
+
+    /// Walks `path` from `root` as far as it resolves, JSON-pointer style:
+    /// a path that runs past a leaf just stops there instead of failing.
+    fn nearest_mut(&mut self, root: usize, path: &[PathSegment]) -> NodeCursor<'_> {
+        let mut current = root;
+        let mut matched = 0;
+        for segment in path {
+            match self.resolve_segment(current, segment) {
+                Some(next) => {
+                    current = next;
+                    matched += 1;
+                },
+                None => break,
+            }
+        }
+        NodeCursor {
+            id: current,
+            node: &mut self.nodes[current],
+            matched,
+        }
+    }
+}
+
+/// Iterator over one node's children inside an `Arena`. Reentrant: every
+/// instance owns its own `Rc<RefCell<Arena>>`.
+struct StatementIterator {
+    /// current node id in the arena
+    current: usize,
+    /// keep the state
+    step: usize,
+    arena: Rc<RefCell<Arena>>,
 }
 
 /// Statement iterator constructor
-fn stm_iter<'n>(node_ptr: &'static LocalKey<RefCell<usize>>) -> StatementIterator {
-    let current = node_ptr.with(|p| {*p.borrow()});
-    StatementIterator {
-        current,
-        step: RefCell::new(0),
-    }
+fn stm_iter(current: usize, arena: Rc<RefCell<Arena>>) -> StatementIterator {
+    StatementIterator { current, step: 0, arena }
 }
 
+/// Hand-rolled pre-order walk over an `Arena`, driven off an explicit
+/// stack of `StatementIterator` frames so `next()` can check the real
+/// current depth against `limit` before descending further.
+struct PreOrder {
+    pending_root: Option<usize>,
+    stack: Vec<StatementIterator>,
+    arena: Rc<RefCell<Arena>>,
+    limit: usize,
+}
 
-impl Iterator for StatementIterator {
-    type Item = &'static LocalKey<RefCell<usize>>;
+impl PreOrder {
+    fn new(root: usize, arena: Rc<RefCell<Arena>>, limit: usize) -> Self {
+        PreOrder { pending_root: Some(root), stack: Vec::new(), arena, limit }
+    }
+}
+
+impl Iterator for PreOrder {
+    type Item = (usize, usize);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let node: Option<Node> =  NODES.with(|rc_nodes| {
-            match rc_nodes.borrow().arena.get(self.current) {
-                Some(node) => {
-                    Some(node.clone())
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if let Some(root) = self.pending_root.take() {
+            self.stack.push(stm_iter(root, Rc::clone(&self.arena)));
+            return Some((0, root));
+        }
+        loop {
+            let depth = self.stack.len();
+            match self.stack.last_mut()?.next() {
+                Some(id) => {
+                    if depth >= self.limit {
+                        self.arena.borrow_mut().error.get_or_insert(QueryParseError::RecursionLimitExceeded);
+                        return None;
+                    }
+                    self.stack.push(stm_iter(id, Rc::clone(&self.arena)));
+                    return Some((depth, id));
                 },
-                _ => None, 
+                None => {
+                    self.stack.pop();
+                    if self.stack.is_empty() {
+                        return None;
+                    }
+                }
             }
-        });
+        }
+    }
+}
 
-        let new_node = |node: Node| -> () {
-            *self.step.borrow_mut() += 1;
-            let id = nodes_next_id();
-            NODES.with(|rc_nodes| {
-                rc_nodes.borrow_mut().new_node(node);
-            });
-            next_put(id); 
-        };
+impl Iterator for StatementIterator {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let node = self.arena.borrow().get(self.current).cloned()?;
+
+        macro_rules! new_node {
+            ($node:expr) => {{
+                // Evaluate $node (which may read self.step) before bumping
+                // the step counter for the next call.
+                let node = $node;
+                self.step += 1;
+                let id = self.arena.borrow_mut().push_child(self.current, node);
+                return Some(id);
+            }};
+        }
 
         match node {
-            Some(Node::Statement(stm)) => {
+            Node::Statement(stm) => {
                 match stm {
                     Statement::Query(query) => {
-                        let step = *self.step.borrow();
-                        if step == 0 {
-                            new_node(Node::SetExpr(query.body.clone()));
-                            return Some(&NEXT)
+                        if self.step == 0 {
+                            new_node!(Node::SetExpr(query.body.clone()));
                         }
-                        return None;
+                        None
                     },
                     // TODO: Insert
-                    _ => return None,
+                    _ => None,
+                }
+            },
+            Node::Query(query) => {
+                if self.step == 0 {
+                    new_node!(Node::SetExpr(query.body.clone()));
                 }
+                None
             },
-            Some(Node::SetExpr(set_expr)) => {
+            Node::SetExpr(set_expr) => {
                 match set_expr {
                     SetExpr::Select(select) => {
-                        let step = *self.step.borrow();
-                        // Iterate "from"
+                        // Iterate "projection", then "from", then "selection"
+                        if self.step < select.projection.len() {
+                            new_node!(Node::SelectItem(select.projection[self.step].clone()));
+                        }
+                        let step = self.step - select.projection.len();
                         if step < select.from.len() {
-                            new_node(Node::TableWithJoins(select.from[step].clone()));
-                            return Some(&NEXT) 
+                            new_node!(Node::TableWithJoins(select.from[step].clone()));
                         }
-                        // TODO: iterate projection, selection
-                        return None;
+                        let step = step - select.from.len();
+                        if step == 0 {
+                            if let Some(selection) = &select.selection {
+                                new_node!(Node::Expr(selection.clone()));
+                            }
+                        }
+                        None
+                    },
+                    SetExpr::Query(query) => {
+                        if self.step == 0 {
+                            new_node!(Node::Query((*query).clone()));
+                        }
+                        None
                     },
-                    // TODO: Query, SetOperation, Values, Insert
-                    _ => return None,
+                    SetExpr::SetOperation { left, right, .. } => {
+                        match self.step {
+                            0 => new_node!(Node::SetExpr((*left).clone())),
+                            1 => new_node!(Node::SetExpr((*right).clone())),
+                            _ => None,
+                        }
+                    },
+                    SetExpr::Values(values) => {
+                        let mut offset = 0;
+                        for row in &values.0 {
+                            if self.step < offset + row.len() {
+                                new_node!(Node::Expr(row[self.step - offset].clone()));
+                            }
+                            offset += row.len();
+                        }
+                        None
+                    },
+                    // TODO: Insert
+                    _ => None,
                 }
+            },
+            Node::SelectItem(item) => {
+                match item {
+                    SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                        if self.step == 0 {
+                            new_node!(Node::Expr(expr.clone()));
+                        }
+                        None
+                    },
+                    // QualifiedWildcard/Wildcard carry no expression to descend into.
+                    _ => None,
+                }
+            },
+            Node::Expr(expr) => {
+                match expr {
+                    Expr::BinaryOp { left, op, right } => {
+                        match self.step {
+                            0 => new_node!(Node::Expr((*left).clone())),
+                            1 => new_node!(Node::BinaryOperator(op.clone())),
+                            2 => new_node!(Node::Expr((*right).clone())),
+                            _ => None,
+                        }
+                    },
+                    Expr::Value(value) => {
+                        if self.step == 0 {
+                            new_node!(Node::Value(value.clone()));
+                        }
+                        None
+                    },
+                    // TODO: UnaryOp, Nested, Subquery, function calls, etc.
+                    _ => None,
+                }
+            },
+            Node::TableWithJoins(twj) => {
+                // Iterate "relation", then "joins"
+                if self.step == 0 {
+                    new_node!(Node::TableFactor(twj.relation.clone()));
+                }
+                let step = self.step - 1;
+                if step < twj.joins.len() {
+                    new_node!(Node::Join(twj.joins[step].clone()));
+                }
+                None
+            },
+            Node::Join(join) => {
+                if self.step == 0 {
+                    new_node!(Node::TableFactor(join.relation.clone()));
+                }
+                if self.step == 1 {
+                    let constraint = match &join.join_operator {
+                        JoinOperator::Inner(c)
+                        | JoinOperator::LeftOuter(c)
+                        | JoinOperator::RightOuter(c)
+                        | JoinOperator::FullOuter(c) => Some(c),
+                        JoinOperator::CrossJoin | JoinOperator::CrossApply | JoinOperator::OuterApply => None,
+                    };
+                    if let Some(JoinConstraint::On(expr)) = constraint {
+                        new_node!(Node::Expr(expr.clone()));
+                    }
+                }
+                // USING/NATURAL/cross joins carry no expression to descend into.
+                None
+            },
+            Node::TableFactor(factor) => {
+                match factor {
+                    TableFactor::Derived { subquery, .. } => {
+                        if self.step == 0 {
+                            new_node!(Node::Query((*subquery).clone()));
+                        }
+                        None
+                    },
+                    // Table, TableFunction, NestedJoin, etc. are leaves for now.
+                    _ => None,
+                }
+            },
+            // BinaryOperator and Value are leaves.
+            _ => None,
+        }
+    }
+}
+
+/// `Stream` version of `PreOrder`: yields one node id per poll instead of
+/// draining the whole walk synchronously.
+struct NodeStream {
+    inner: PreOrder,
+    /// Keeps this walk registered with its `ReadWriteGate` until dropped.
+    _reader: ReaderGuard,
+}
+
+impl Stream for NodeStream {
+    type Item = usize;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<usize>> {
+        Poll::Ready(self.inner.next().map(|(_, id)| id))
+    }
+}
+
+/// Builds a `NodeStream`, registering it with `gate` as an outstanding
+/// reader for as long as the stream lives.
+fn stream(root: usize, arena: Rc<RefCell<Arena>>, limit: usize, gate: &ReadWriteGate) -> NodeStream {
+    NodeStream { inner: PreOrder::new(root, arena, limit), _reader: gate.reader() }
+}
+
+/// Scheduling gate for the "many shared readers, then one exclusive
+/// writer" pattern: any number of read-only `NodeStream`s may run
+/// concurrently, but a mutating rewrite pass waits until every
+/// outstanding reader has finished before it starts.
+#[derive(Clone)]
+struct ReadWriteGate {
+    inner: Rc<RefCell<ReadWriteGateState>>,
+}
+
+#[derive(Default)]
+struct ReadWriteGateState {
+    readers: usize,
+    /// Wakers of writers currently waiting in `ReadersDrained`, woken
+    /// once `readers` drops back to zero.
+    wakers: Vec<Waker>,
+}
+
+impl ReadWriteGate {
+    fn new() -> Self {
+        ReadWriteGate { inner: Rc::new(RefCell::new(ReadWriteGateState::default())) }
+    }
+
+    /// Registers one outstanding reader; the returned guard deregisters
+    /// it again when dropped, i.e. when that reader's `NodeStream` is
+    /// dropped. Exhausting the stream alone does not deregister it.
+    fn reader(&self) -> ReaderGuard {
+        self.inner.borrow_mut().readers += 1;
+        ReaderGuard { inner: Rc::clone(&self.inner) }
+    }
+
+    /// Waits until all outstanding readers have finished, then runs
+    /// `write` with exclusive access.
+    async fn write<F: FnOnce()>(&self, write: F) {
+        ReadersDrained { inner: Rc::clone(&self.inner) }.await;
+        write();
+    }
+
+    /// The number of readers currently registered.
+    fn outstanding(&self) -> usize {
+        self.inner.borrow().readers
+    }
+}
+
+struct ReaderGuard {
+    inner: Rc<RefCell<ReadWriteGateState>>,
+}
+
+impl Drop for ReaderGuard {
+    fn drop(&mut self) {
+        let mut state = self.inner.borrow_mut();
+        state.readers -= 1;
+        if state.readers == 0 {
+            for waker in state.wakers.drain(..) {
+                waker.wake();
             }
-            // TODO: other nodes
-            _ => return None, 
+        }
+    }
+}
+
+/// Future that resolves once `ReadWriteGate` has no outstanding readers.
+struct ReadersDrained {
+    inner: Rc<RefCell<ReadWriteGateState>>,
+}
+
+impl Future for ReadersDrained {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.inner.borrow_mut();
+        if state.readers == 0 {
+            Poll::Ready(())
+        } else {
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
 
 // Main
 
-fn main() {
-    let query = "select a, b from t where a = 1";
-    parse_sql(query).unwrap();
+/// Entry point for parsing a SQL string and walking its AST. Builder
+/// style so callers can tune the recursion limit before `parse_sql`.
+struct QueryTraversal {
+    recursion_limit: usize,
 }
 
-fn parse_sql(sql: &str) -> Result<(), QueryParseError> {
-    let dialect = GenericDialect {};
-    let statements = Parser::parse_sql(&dialect, sql)?;
-    for stm in statements {
-        println!("{:?}", stm);
-        let top = nodes_next_id();
-        NODES.with(|rc_nodes| {
-            rc_nodes.borrow_mut().new_node(Node::Statement(stm));
-        });
-        next_put(top);
-        let dft_pre = DftPre::new(&NEXT, |node| stm_iter(node));
-        for (_level, node) in dft_pre {
-            let id = node.with(|p| { *p.borrow() } );
-            NODES.with(|rc_nodes| {
-                if let Some(node) = rc_nodes.borrow().arena.get(id) {
+impl QueryTraversal {
+    fn new() -> Self {
+        QueryTraversal { recursion_limit: DEFAULT_RECURSION_LIMIT }
+    }
+
+    /// Caps how many levels deep the walk may descend before it gives up
+    /// with `QueryParseError::RecursionLimitExceeded` instead of blowing
+    /// the stack on a pathologically nested query.
+    fn with_recursion_limit(mut self, limit: usize) -> Self {
+        self.recursion_limit = limit;
+        self
+    }
+
+    fn parse_sql(&self, sql: &str) -> Result<(), QueryParseError> {
+        let dialect = GenericDialect {};
+        let statements = Parser::parse_sql(&dialect, sql)?;
+        for stm in statements {
+            println!("{:?}", stm);
+            let arena = Rc::new(RefCell::new(Arena::new()));
+            let root = arena.borrow_mut().push(Node::Statement(stm));
+            for (_level, id) in PreOrder::new(root, Rc::clone(&arena), self.recursion_limit) {
+                if let Some(node) = arena.borrow().get(id) {
                     println!("{:?}", node);
                 }
+            }
+            let err = arena.borrow_mut().error.take();
+            if let Some(err) = err {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let query = "select a, b from t where a = 1";
+    QueryTraversal::new().parse_sql(query).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursion_limit_is_enforced_by_real_walk_depth() {
+        let query = "select a, b from t where a = 1";
+        let err = QueryTraversal::new()
+            .with_recursion_limit(2)
+            .parse_sql(query)
+            .unwrap_err();
+        assert!(matches!(err, QueryParseError::RecursionLimitExceeded));
+    }
+
+    #[test]
+    fn shallow_walk_stays_within_the_default_limit() {
+        let query = "select a from t";
+        assert!(QueryTraversal::new().parse_sql(query).is_ok());
+    }
+
+    #[test]
+    fn interleaved_walks_do_not_share_state() {
+        let first = Rc::new(RefCell::new(Arena::new()));
+        let first_root = first.borrow_mut().push(Node::Statement(
+            Parser::parse_sql(&GenericDialect {}, "select a from t").unwrap().remove(0),
+        ));
+        let second = Rc::new(RefCell::new(Arena::new()));
+        let second_root = second.borrow_mut().push(Node::Statement(
+            Parser::parse_sql(&GenericDialect {}, "select b, c from u").unwrap().remove(0),
+        ));
+
+        let mut walk_a = PreOrder::new(first_root, Rc::clone(&first), DEFAULT_RECURSION_LIMIT);
+        let mut walk_b = PreOrder::new(second_root, Rc::clone(&second), DEFAULT_RECURSION_LIMIT);
+
+        // Step the two walks in lockstep so their StatementIterator stacks
+        // are alive and advancing at the same time, then drain each fully
+        // and check neither leaked nodes into the other's arena.
+        loop {
+            let a = walk_a.next().is_some();
+            let b = walk_b.next().is_some();
+            if !a && !b {
+                break;
+            }
+        }
+
+        assert_eq!(first.borrow().nodes.len(), 6);
+        assert_eq!(second.borrow().nodes.len(), 8);
+    }
+
+    #[test]
+    fn ancestors_climb_back_through_a_nested_subquery() {
+        let stm = Parser::parse_sql(
+            &GenericDialect {},
+            "select a from (select b from t where b = 1) as sub",
+        )
+        .unwrap()
+        .remove(0);
+        let arena = Rc::new(RefCell::new(Arena::new()));
+        let root = arena.borrow_mut().push(Node::Statement(stm));
+        let mut innermost = root;
+        for (_level, id) in PreOrder::new(root, Rc::clone(&arena), DEFAULT_RECURSION_LIMIT) {
+            if matches!(arena.borrow().get(id), Some(Node::Value(_))) {
+                innermost = id;
+            }
+        }
+
+        let chain: Vec<usize> = arena.borrow().ancestors(innermost).collect();
+        assert_eq!(chain.last().copied(), Some(root));
+        assert_eq!(arena.borrow().root_of(innermost), root);
+    }
+
+    #[test]
+    fn join_on_condition_is_visited() {
+        let stm = Parser::parse_sql(
+            &GenericDialect {},
+            "select a, b from t join u on t.id = u.id where a = 1",
+        )
+        .unwrap()
+        .remove(0);
+        let arena = Rc::new(RefCell::new(Arena::new()));
+        let root = arena.borrow_mut().push(Node::Statement(stm));
+
+        let visited_on_condition = PreOrder::new(root, Rc::clone(&arena), DEFAULT_RECURSION_LIMIT)
+            .any(|(_, id)| match arena.borrow().get(id) {
+                Some(Node::Expr(Expr::BinaryOp { op: BinaryOperator::Eq, .. })) => {
+                    format!("{:?}", arena.borrow().get(id)).contains("id")
+                },
+                _ => false,
             });
+        assert!(visited_on_condition, "walk never reached the join's ON condition");
+    }
+
+    #[test]
+    fn nearest_mut_resolves_an_index_into_a_multi_table_from_list() {
+        let stm = Parser::parse_sql(&GenericDialect {}, "select a from t, u")
+            .unwrap()
+            .remove(0);
+        let arena = Rc::new(RefCell::new(Arena::new()));
+        let root = arena.borrow_mut().push(Node::Statement(stm));
+        // Drive the walk once so the arena actually has the FROM-list
+        // children nearest_mut will navigate.
+        for _ in PreOrder::new(root, Rc::clone(&arena), DEFAULT_RECURSION_LIMIT) {}
+
+        let path = [
+            PathSegment::Field("body"),
+            PathSegment::Field("from"),
+            PathSegment::Index(1),
+            PathSegment::Field("relation"),
+        ];
+        let mut arena_mut = arena.borrow_mut();
+        let cursor = arena_mut.nearest_mut(root, &path);
+        assert_eq!(cursor.matched, path.len());
+        match cursor.node {
+            Node::TableFactor(factor) => assert!(format!("{:?}", factor).contains("\"u\"")),
+            other => panic!("expected the second FROM table's relation, got {:?}", other),
         }
     }
-    Ok(())
+
+    #[test]
+    fn concurrent_node_streams_drain_the_gate() {
+        use futures::stream::StreamExt;
+
+        let gate = ReadWriteGate::new();
+        let queries = ["select a from t", "select b, c from u", "select d from v"];
+        let streams: Vec<NodeStream> = queries
+            .iter()
+            .map(|sql| {
+                let stm = Parser::parse_sql(&GenericDialect {}, sql).unwrap().remove(0);
+                let arena = Rc::new(RefCell::new(Arena::new()));
+                let root = arena.borrow_mut().push(Node::Statement(stm));
+                stream(root, arena, DEFAULT_RECURSION_LIMIT, &gate)
+            })
+            .collect();
+
+        assert_eq!(gate.outstanding(), 3);
+
+        futures::executor::block_on(async {
+            for mut s in streams {
+                while s.next().await.is_some() {}
+            }
+        });
+
+        assert_eq!(gate.outstanding(), 0);
+    }
+
+    #[test]
+    fn write_waits_until_every_reader_has_drained() {
+        use futures::stream::StreamExt;
+        use std::cell::Cell;
+
+        let gate = ReadWriteGate::new();
+        let stm = Parser::parse_sql(&GenericDialect {}, "select a from t")
+            .unwrap()
+            .remove(0);
+        let arena = Rc::new(RefCell::new(Arena::new()));
+        let root = arena.borrow_mut().push(Node::Statement(stm));
+        let mut reader = stream(root, arena, DEFAULT_RECURSION_LIMIT, &gate);
+        assert_eq!(gate.outstanding(), 1);
+
+        let ran = Rc::new(Cell::new(false));
+        let ran_in_write = Rc::clone(&ran);
+        let mut write = Box::pin(gate.write(move || ran_in_write.set(true)));
+
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+
+        // The reader is still outstanding, so write must stay pending and
+        // must not have run its closure yet.
+        assert!(write.as_mut().poll(&mut cx).is_pending());
+        assert!(!ran.get());
+
+        futures::executor::block_on(async { while reader.next().await.is_some() {} });
+        drop(reader);
+        assert_eq!(gate.outstanding(), 0);
+
+        assert!(write.as_mut().poll(&mut cx).is_ready());
+        assert!(ran.get());
+    }
 }